@@ -0,0 +1,617 @@
+use crate::progress::{Progress, Stats};
+use crate::retry::{with_retry, Semaphore};
+use crate::s3path::S3Path;
+use crate::Error;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use rusoto_core::ByteStream;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest,
+    CompletedMultipartUpload, CompletedPart, CopyObjectRequest,
+    CreateMultipartUploadRequest, GetObjectRequest, GetObjectTaggingRequest,
+    HeadObjectRequest, PutObjectRequest, PutObjectTaggingRequest, S3Client,
+    Tagging, UploadPartCopyRequest, UploadPartRequest, S3,
+};
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Largest object size (bytes) that `CopyObjectRequest` can handle in a
+/// single call; anything bigger must go through the multipart copy path.
+const MAX_SINGLE_COPY_SIZE: i64 = 5 * 1024 * 1024 * 1024;
+
+/// Size of each part used when copying an object via multipart copy.
+const COPY_PART_SIZE: i64 = 512 * 1024 * 1024;
+
+/// Maximum number of `UploadPartRequest` calls in flight at once.
+const UPLOAD_PART_CONCURRENCY: usize = 4;
+
+/// Hard limit on the number of parts in a single multipart upload, per the
+/// S3 API (`CompleteMultipartUpload` rejects anything beyond this).
+const MAX_PART_COUNT: u64 = 10_000;
+
+/// Characters (beyond what `CONTROLS` already covers) that must be
+/// percent-encoded in an `x-amz-copy-source` value; `/` is left alone since
+/// it separates the bucket from the key.
+const COPY_SOURCE_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}')
+    .add(b'+');
+
+pub(crate) fn cp_action(
+    s3: &S3Client,
+    dst_s3: &S3Client,
+    src_path: &Arc<Mutex<S3Path>>,
+    dst_path: &Arc<Mutex<S3Path>>,
+    matching_obj: &rusoto_s3::Object,
+    force_download: bool,
+    same_destination: bool,
+    part_size: u64,
+    storage_class: Option<&str>,
+    tags: bool,
+    stats: &Arc<Mutex<Stats>>,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let (src_bucket, src_key) = {
+        let src_path = src_path.lock().unwrap();
+        (src_path.bucket.clone(), src_path.key.clone())
+    };
+
+    let src_obj_key = matching_obj.key.clone().unwrap();
+    let rel_key = relative_key(&src_obj_key, &src_key);
+
+    let (dst_bucket, dst_key) = {
+        let dst_path = dst_path.lock().unwrap();
+        (dst_path.bucket.clone(), dst_path.key.clone())
+    };
+
+    let dst_key = dest_key(&dst_key, &rel_key);
+
+    // Fall back to the source object's own storage class when no override
+    // was given; `CopyObjectRequest` otherwise silently defaults to
+    // `STANDARD` instead of preserving it.
+    let storage_class = storage_class
+        .map(str::to_owned)
+        .or_else(|| matching_obj.storage_class.clone());
+
+    let copy_tags_if_enabled = |src_bucket: &str, src_key: &str| {
+        if tags {
+            if let Err(err) =
+                copy_tags(s3, dst_s3, src_bucket, src_key, &dst_bucket, &dst_key)
+            {
+                eprintln!("Failed to copy tags for {}: {}", src_key, err);
+            }
+        }
+    };
+
+    if !force_download && same_destination {
+        let copy_res = server_side_copy(
+            s3,
+            dst_s3,
+            &src_bucket,
+            &src_obj_key,
+            &dst_bucket,
+            &dst_key,
+            matching_obj.size,
+            storage_class.as_deref(),
+            progress,
+        );
+
+        match copy_res {
+            Ok(()) => {
+                progress.println(format!(
+                    "{} -> {}, content-length: {} (server-side copy)",
+                    rel_key,
+                    dst_key,
+                    matching_obj.size.unwrap_or(0)
+                ));
+
+                stats
+                    .lock()
+                    .unwrap()
+                    .record_success(matching_obj.size.unwrap_or(0) as u64);
+
+                copy_tags_if_enabled(&src_bucket, &src_obj_key);
+
+                return Ok(());
+            }
+            Err(err) => {
+                eprintln!(
+                    "Server-side copy of {} failed ({}), falling back to \
+                     GET+PUT",
+                    src_obj_key, err
+                );
+            }
+        }
+    }
+
+    let get_obj_req = GetObjectRequest {
+        bucket: src_bucket.clone(),
+        key: src_obj_key.clone(),
+        ..Default::default()
+    };
+
+    let mut get_obj_output =
+        with_retry(|| s3.get_object(get_obj_req.clone()).sync())?;
+
+    progress.println(format!(
+        "{} -> {}, content-length: {}",
+        rel_key,
+        dst_key,
+        get_obj_output.content_length.unwrap()
+    ));
+
+    let content_length = get_obj_output.content_length.unwrap_or(0) as u64;
+
+    if content_length > part_size {
+        multipart_upload(
+            dst_s3,
+            &dst_bucket,
+            &dst_key,
+            get_obj_output.content_type,
+            get_obj_output.metadata,
+            get_obj_output.cache_control,
+            get_obj_output.content_encoding,
+            get_obj_output.expires,
+            storage_class.clone(),
+            get_obj_output.body.unwrap(),
+            part_size,
+            content_length,
+            progress,
+        )?;
+
+        stats.lock().unwrap().record_success(content_length);
+
+        copy_tags_if_enabled(&src_bucket, &src_obj_key);
+
+        return Ok(());
+    }
+
+    // Buffer the (small) body so a failed PUT can be retried without
+    // re-fetching the source object.
+    let mut body_buf = Vec::new();
+    get_obj_output
+        .body
+        .take()
+        .unwrap()
+        .into_blocking_read()
+        .read_to_end(&mut body_buf)?;
+
+    with_retry(|| {
+        let put_obj_req = PutObjectRequest {
+            bucket: dst_bucket.clone(),
+            key: dst_key.clone(),
+            body: Some(body_buf.clone().into()),
+            content_disposition: get_obj_output.content_disposition.clone(),
+            content_language: get_obj_output.content_language.clone(),
+            content_length: get_obj_output.content_length,
+            content_type: get_obj_output.content_type.clone(),
+            metadata: get_obj_output.metadata.clone(),
+            cache_control: get_obj_output.cache_control.clone(),
+            content_encoding: get_obj_output.content_encoding.clone(),
+            expires: get_obj_output.expires.clone(),
+            storage_class: storage_class.clone(),
+            ..Default::default()
+        };
+
+        dst_s3.put_object(put_obj_req).sync()
+    })?;
+
+    stats.lock().unwrap().record_success(content_length);
+
+    copy_tags_if_enabled(&src_bucket, &src_obj_key);
+
+    Ok(())
+}
+
+/// Strips `src_prefix` (the source listing's prefix) and any leading `/`
+/// off `src_obj_key`, leaving the portion of the key relative to it.
+fn relative_key(src_obj_key: &str, src_prefix: &str) -> String {
+    src_obj_key
+        .trim_start_matches(src_prefix)
+        .trim_start_matches('/')
+        .to_owned()
+}
+
+/// Joins `dst_prefix` and `rel_key` with a single `/`, regardless of
+/// whether `dst_prefix` already ends in one.
+fn dest_key(dst_prefix: &str, rel_key: &str) -> String {
+    format!("{}/{}", dst_prefix.trim_end_matches('/'), rel_key)
+}
+
+/// Copies the source object's tag set to the destination object, following
+/// the explicit `GetObjectTagging`/`PutObjectTagging` round-trip used by
+/// tools like `s3find` rather than relying on `CopyObjectRequest`'s
+/// (implicit, and not always honored by S3-compatible backends) tagging
+/// directive.
+fn copy_tags(
+    s3: &S3Client,
+    dst_s3: &S3Client,
+    src_bucket: &str,
+    src_key: &str,
+    dst_bucket: &str,
+    dst_key: &str,
+) -> Result<(), Error> {
+    let get_tagging_req = GetObjectTaggingRequest {
+        bucket: src_bucket.to_owned(),
+        key: src_key.to_owned(),
+        ..Default::default()
+    };
+
+    let tag_set = s3.get_object_tagging(get_tagging_req).sync()?.tag_set;
+
+    if tag_set.is_empty() {
+        return Ok(());
+    }
+
+    let put_tagging_req = PutObjectTaggingRequest {
+        bucket: dst_bucket.to_owned(),
+        key: dst_key.to_owned(),
+        tagging: Tagging { tag_set },
+        ..Default::default()
+    };
+
+    dst_s3.put_object_tagging(put_tagging_req).sync()?;
+
+    Ok(())
+}
+
+/// Uploads `body` to `dst_bucket`/`dst_key` as a multipart upload, reading
+/// it in `part_size`-sized chunks so that large GET+PUT transfers never
+/// have to hold the whole object in memory.
+fn multipart_upload(
+    dst_s3: &S3Client,
+    dst_bucket: &str,
+    dst_key: &str,
+    content_type: Option<String>,
+    metadata: Option<HashMap<String, String>>,
+    cache_control: Option<String>,
+    content_encoding: Option<String>,
+    expires: Option<String>,
+    storage_class: Option<String>,
+    body: ByteStream,
+    part_size: u64,
+    total_size: u64,
+    progress: &Progress,
+) -> Result<(), Error> {
+    // S3 rejects a `CompleteMultipartUpload` with more than `MAX_PART_COUNT`
+    // parts, so scale `part_size` up rather than let a large object with a
+    // small `--part-size` fail opaquely at the very end of the transfer.
+    let min_part_size = (total_size + MAX_PART_COUNT - 1) / MAX_PART_COUNT;
+    let part_size = if part_size < min_part_size {
+        eprintln!(
+            "warning: part size {} would exceed the {}-part limit for \
+             {}-byte object {}; using {} instead",
+            part_size, MAX_PART_COUNT, total_size, dst_key, min_part_size
+        );
+        min_part_size
+    } else {
+        part_size
+    };
+
+    let part_bar = progress.part_bar(dst_key, total_size);
+    let create_req = CreateMultipartUploadRequest {
+        bucket: dst_bucket.to_owned(),
+        key: dst_key.to_owned(),
+        content_type,
+        metadata,
+        cache_control,
+        content_encoding,
+        expires,
+        storage_class,
+        ..Default::default()
+    };
+
+    let upload_id = with_retry(|| dst_s3.create_multipart_upload(create_req.clone()).sync())?
+        .upload_id
+        .unwrap();
+
+    let abort_on_err = |err: Error| -> Error {
+        let abort_req = AbortMultipartUploadRequest {
+            bucket: dst_bucket.to_owned(),
+            key: dst_key.to_owned(),
+            upload_id: upload_id.clone(),
+            ..Default::default()
+        };
+
+        if let Err(abort_err) = dst_s3.abort_multipart_upload(abort_req).sync() {
+            eprintln!(
+                "Failed to abort multipart upload {}: {}",
+                upload_id, abort_err
+            );
+        }
+
+        err
+    };
+
+    let semaphore = Arc::new(Semaphore::new(UPLOAD_PART_CONCURRENCY));
+    let mut handles = Vec::new();
+    let mut reader = body.into_blocking_read();
+    let mut part_number = 1;
+
+    loop {
+        let mut buf = vec![0u8; part_size as usize];
+        let mut filled = 0;
+
+        while filled < buf.len() {
+            let read = reader
+                .read(&mut buf[filled..])
+                .map_err(|err| abort_on_err(err.into()))?;
+
+            if read == 0 {
+                break;
+            }
+
+            filled += read;
+        }
+
+        if filled == 0 {
+            break;
+        }
+
+        buf.truncate(filled);
+
+        let digest = md5::compute(&buf);
+        let content_md5 = base64::encode(&digest.0);
+
+        semaphore.acquire();
+
+        let dst_s3 = dst_s3.clone();
+        let semaphore = semaphore.clone();
+        let dst_bucket = dst_bucket.to_owned();
+        let dst_key = dst_key.to_owned();
+        let upload_id = upload_id.clone();
+        let part_bar = part_bar.clone();
+        let part_len = buf.len() as u64;
+
+        handles.push(thread::spawn(move || {
+            let res = with_retry(|| {
+                let upload_part_req = UploadPartRequest {
+                    bucket: dst_bucket.clone(),
+                    key: dst_key.clone(),
+                    upload_id: upload_id.clone(),
+                    part_number,
+                    body: Some(buf.clone().into()),
+                    content_md5: Some(content_md5.clone()),
+                    ..Default::default()
+                };
+
+                dst_s3.upload_part(upload_part_req).sync()
+            });
+            semaphore.release();
+
+            if res.is_ok() {
+                part_bar.inc(part_len);
+            }
+
+            res.map(|output| CompletedPart {
+                e_tag: output.e_tag,
+                part_number: Some(part_number),
+            })
+        }));
+
+        part_number += 1;
+    }
+
+    let mut parts = Vec::new();
+
+    for handle in handles {
+        let part = handle
+            .join()
+            .expect("upload part thread panicked")
+            .map_err(|err| abort_on_err(err.into()))?;
+
+        parts.push(part);
+    }
+
+    let complete_req = CompleteMultipartUploadRequest {
+        bucket: dst_bucket.to_owned(),
+        key: dst_key.to_owned(),
+        upload_id,
+        multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+        ..Default::default()
+    };
+
+    with_retry(|| dst_s3.complete_multipart_upload(complete_req.clone()).sync())
+        .map_err(|err| abort_on_err(err.into()))?;
+
+    part_bar.finish_and_clear();
+
+    Ok(())
+}
+
+/// Copies `src_bucket`/`src_key` to `dst_bucket`/`dst_key` without pulling
+/// the object through this process, routing through the multipart copy
+/// path when the object is too large for a single `CopyObjectRequest`.
+fn server_side_copy(
+    s3: &S3Client,
+    dst_s3: &S3Client,
+    src_bucket: &str,
+    src_key: &str,
+    dst_bucket: &str,
+    dst_key: &str,
+    size: Option<i64>,
+    storage_class: Option<&str>,
+    progress: &Progress,
+) -> Result<(), Error> {
+    // `CopyObjectRequest`/`UploadPartCopyRequest` read `copy_source` as a
+    // literal `x-amz-copy-source` header value, so the key must be
+    // percent-encoded or keys with spaces, `+`, `?`, `#` or non-ASCII bytes
+    // produce a malformed header (or copy the wrong object).
+    let copy_source = format!(
+        "{}/{}",
+        src_bucket,
+        utf8_percent_encode(src_key, COPY_SOURCE_ENCODE_SET)
+    );
+
+    if size.unwrap_or(0) > MAX_SINGLE_COPY_SIZE {
+        return multipart_copy(
+            s3,
+            dst_s3,
+            src_bucket,
+            src_key,
+            &copy_source,
+            dst_bucket,
+            dst_key,
+            size.unwrap(),
+            storage_class,
+            progress,
+        );
+    }
+
+    let copy_obj_req = CopyObjectRequest {
+        bucket: dst_bucket.to_owned(),
+        key: dst_key.to_owned(),
+        copy_source,
+        storage_class: storage_class.map(str::to_owned),
+        ..Default::default()
+    };
+
+    with_retry(|| dst_s3.copy_object(copy_obj_req.clone()).sync())?;
+
+    Ok(())
+}
+
+/// Copies an object larger than [`MAX_SINGLE_COPY_SIZE`] by splitting it
+/// into `COPY_PART_SIZE`-sized ranges and copying each via
+/// `UploadPartCopyRequest`, as a single `CopyObjectRequest` would be
+/// rejected by S3.
+fn multipart_copy(
+    s3: &S3Client,
+    dst_s3: &S3Client,
+    src_bucket: &str,
+    src_key: &str,
+    copy_source: &str,
+    dst_bucket: &str,
+    dst_key: &str,
+    size: i64,
+    storage_class: Option<&str>,
+    progress: &Progress,
+) -> Result<(), Error> {
+    let part_bar = progress.part_bar(dst_key, size as u64);
+
+    // `UploadPartCopyRequest` carries no metadata of its own, so unlike the
+    // GET+PUT multipart path this one has to fetch it separately up front
+    // or it's silently dropped on the destination object.
+    let head_obj_req = HeadObjectRequest {
+        bucket: src_bucket.to_owned(),
+        key: src_key.to_owned(),
+        ..Default::default()
+    };
+
+    let head_obj_output = with_retry(|| s3.head_object(head_obj_req.clone()).sync())?;
+
+    let create_req = CreateMultipartUploadRequest {
+        bucket: dst_bucket.to_owned(),
+        key: dst_key.to_owned(),
+        content_type: head_obj_output.content_type,
+        metadata: head_obj_output.metadata,
+        cache_control: head_obj_output.cache_control,
+        content_encoding: head_obj_output.content_encoding,
+        expires: head_obj_output.expires,
+        storage_class: storage_class.map(str::to_owned),
+        ..Default::default()
+    };
+
+    let upload_id = with_retry(|| dst_s3.create_multipart_upload(create_req.clone()).sync())?
+        .upload_id
+        .unwrap();
+
+    let abort_on_err = |err: Error| -> Error {
+        let abort_req = AbortMultipartUploadRequest {
+            bucket: dst_bucket.to_owned(),
+            key: dst_key.to_owned(),
+            upload_id: upload_id.clone(),
+            ..Default::default()
+        };
+
+        if let Err(abort_err) =
+            dst_s3.abort_multipart_upload(abort_req).sync()
+        {
+            eprintln!(
+                "Failed to abort multipart upload {}: {}",
+                upload_id, abort_err
+            );
+        }
+
+        err
+    };
+
+    let mut parts = Vec::new();
+    let mut part_number = 1;
+    let mut start = 0i64;
+
+    while start < size {
+        let end = std::cmp::min(start + COPY_PART_SIZE, size) - 1;
+
+        let upload_part_req = UploadPartCopyRequest {
+            bucket: dst_bucket.to_owned(),
+            key: dst_key.to_owned(),
+            upload_id: upload_id.clone(),
+            part_number,
+            copy_source: copy_source.to_owned(),
+            copy_source_range: Some(format!("bytes={}-{}", start, end)),
+            ..Default::default()
+        };
+
+        let copy_part_result =
+            with_retry(|| dst_s3.upload_part_copy(upload_part_req.clone()).sync())
+                .map_err(|err| abort_on_err(err.into()))?
+                .copy_part_result
+                .unwrap();
+
+        parts.push(CompletedPart {
+            e_tag: copy_part_result.e_tag,
+            part_number: Some(part_number),
+        });
+
+        part_bar.inc((end - start + 1) as u64);
+        part_number += 1;
+        start = end + 1;
+    }
+
+    let complete_req = CompleteMultipartUploadRequest {
+        bucket: dst_bucket.to_owned(),
+        key: dst_key.to_owned(),
+        upload_id,
+        multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+        ..Default::default()
+    };
+
+    with_retry(|| dst_s3.complete_multipart_upload(complete_req.clone()).sync())
+        .map_err(|err| abort_on_err(err.into()))?;
+
+    part_bar.finish_and_clear();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_key_strips_prefix_and_leading_slash() {
+        assert_eq!(relative_key("prefix/a/b.txt", "prefix"), "a/b.txt");
+        assert_eq!(relative_key("prefix/b.txt", "prefix/"), "b.txt");
+    }
+
+    #[test]
+    fn relative_key_with_no_shared_prefix_is_unchanged() {
+        assert_eq!(relative_key("a/b.txt", "other"), "a/b.txt");
+    }
+
+    #[test]
+    fn dest_key_joins_with_a_single_slash() {
+        assert_eq!(dest_key("dst", "a/b.txt"), "dst/a/b.txt");
+        assert_eq!(dest_key("dst/", "a/b.txt"), "dst/a/b.txt");
+    }
+}
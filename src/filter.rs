@@ -0,0 +1,224 @@
+use crate::s3path::S3Path;
+use crate::Error;
+use chrono::{DateTime, Utc};
+use glob::Pattern;
+use regex::Regex;
+use rusoto_s3::{ListObjectsV2Request, S3Client, S3};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use structopt::StructOpt;
+
+/// Filter flags shared by every subcommand that walks a bucket prefix.
+#[derive(Debug, StructOpt)]
+pub(crate) struct FilterArgs {
+    /// Only match keys against this glob pattern
+    #[structopt(long)]
+    pub name: Option<String>,
+
+    /// Only match keys against this regular expression
+    #[structopt(long)]
+    pub regex: Option<String>,
+
+    /// Only match objects by size in bytes: `+N` (larger), `-N` (smaller)
+    /// or `N` (exactly)
+    #[structopt(long)]
+    pub size: Option<String>,
+
+    /// Only match objects by last-modified age in days: `+N` (older),
+    /// `-N` (younger) or `N` (exactly)
+    #[structopt(long)]
+    pub mtime: Option<String>,
+}
+
+/// A `+N`/`-N`/`N` style comparison, as used by `find`'s `-size`/`-mtime`.
+enum NumberFilter {
+    Greater(i64),
+    Less(i64),
+    Equal(i64),
+}
+
+impl FromStr for NumberFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('+') {
+            Ok(NumberFilter::Greater(s[1..].parse()?))
+        } else if s.starts_with('-') {
+            Ok(NumberFilter::Less(s[1..].parse()?))
+        } else {
+            Ok(NumberFilter::Equal(s.parse()?))
+        }
+    }
+}
+
+impl NumberFilter {
+    fn matches(&self, value: i64) -> bool {
+        match self {
+            NumberFilter::Greater(n) => value > *n,
+            NumberFilter::Less(n) => value < *n,
+            NumberFilter::Equal(n) => value == *n,
+        }
+    }
+}
+
+/// Compiled form of [`FilterArgs`], applied to each `rusoto_s3::Object`
+/// returned while walking a prefix.
+pub(crate) struct Filter {
+    name: Option<Pattern>,
+    regex: Option<Regex>,
+    size: Option<NumberFilter>,
+    mtime: Option<NumberFilter>,
+}
+
+impl Filter {
+    pub(crate) fn new(args: &FilterArgs) -> Result<Self, Error> {
+        Ok(Filter {
+            name: args.name.as_deref().map(Pattern::new).transpose()?,
+            regex: args.regex.as_deref().map(Regex::new).transpose()?,
+            size: args.size.as_deref().map(str::parse).transpose()?,
+            mtime: args.mtime.as_deref().map(str::parse).transpose()?,
+        })
+    }
+
+    pub(crate) fn matches(&self, obj: &rusoto_s3::Object) -> bool {
+        let key = match &obj.key {
+            Some(key) => key.as_str(),
+            None => return false,
+        };
+
+        if let Some(name) = &self.name {
+            if !name.matches(key) {
+                return false;
+            }
+        }
+
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(key) {
+                return false;
+            }
+        }
+
+        if let Some(size) = &self.size {
+            if !size.matches(obj.size.unwrap_or(0)) {
+                return false;
+            }
+        }
+
+        if let Some(mtime) = &self.mtime {
+            let age_days = obj
+                .last_modified
+                .as_ref()
+                .and_then(|s| age_in_days(s))
+                .unwrap_or(0);
+
+            if !mtime.matches(age_days) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Number of whole days between `last_modified` (an S3 ISO-8601 timestamp)
+/// and now.
+fn age_in_days(last_modified: &str) -> Option<i64> {
+    let modified = DateTime::parse_from_rfc3339(last_modified).ok()?;
+    Some(Utc::now().signed_duration_since(modified).num_days())
+}
+
+/// Walks every object under `src_path`'s prefix, applying `filter`, and
+/// calls `on_match` for each object that passes. Shared by every
+/// subcommand so `cp`, `ls`, `rm` and `exec` all see the same filtering
+/// behavior.
+pub(crate) fn for_each_matching_object<F>(
+    s3: &S3Client,
+    src_path: &Arc<Mutex<S3Path>>,
+    filter: &Filter,
+    mut on_match: F,
+) -> Result<(), Error>
+where
+    F: FnMut(rusoto_s3::Object) -> Result<(), Error>,
+{
+    let mut is_truncated = true;
+    let mut next_continuation_token = None;
+
+    while is_truncated {
+        let list_objs_req = {
+            let src_path = src_path.lock().unwrap();
+
+            ListObjectsV2Request {
+                bucket: src_path.bucket.clone(),
+                prefix: Some(src_path.key.clone()),
+                continuation_token: next_continuation_token,
+                ..Default::default()
+            }
+        };
+
+        let list_obj_output = s3.list_objects_v2(list_objs_req).sync()?;
+
+        let matching_objs = list_obj_output
+            .contents
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|obj| filter.matches(obj));
+
+        for matching_obj in matching_objs {
+            on_match(matching_obj)?;
+        }
+
+        is_truncated = list_obj_output.is_truncated.unwrap_or(false);
+        next_continuation_token =
+            list_obj_output.next_continuation_token.clone();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn number_filter_parses_greater_less_and_equal() {
+        assert!(matches!("+5".parse(), Ok(NumberFilter::Greater(5))));
+        assert!(matches!("-5".parse(), Ok(NumberFilter::Less(5))));
+        assert!(matches!("5".parse(), Ok(NumberFilter::Equal(5))));
+    }
+
+    #[test]
+    fn number_filter_matches_by_sign() {
+        let greater: NumberFilter = "+10".parse().unwrap();
+        assert!(greater.matches(11));
+        assert!(!greater.matches(10));
+        assert!(!greater.matches(9));
+
+        let less: NumberFilter = "-10".parse().unwrap();
+        assert!(less.matches(9));
+        assert!(!less.matches(10));
+        assert!(!less.matches(11));
+
+        let equal: NumberFilter = "10".parse().unwrap();
+        assert!(equal.matches(10));
+        assert!(!equal.matches(9));
+    }
+
+    #[test]
+    fn number_filter_rejects_non_numeric_input() {
+        assert!("+abc".parse::<NumberFilter>().is_err());
+    }
+
+    #[test]
+    fn age_in_days_computes_whole_days_since_now() {
+        let ten_days_ago = Utc::now() - Duration::days(10);
+        let age = age_in_days(&ten_days_ago.to_rfc3339()).unwrap();
+
+        assert_eq!(age, 10);
+    }
+
+    #[test]
+    fn age_in_days_rejects_malformed_timestamps() {
+        assert_eq!(age_in_days("not-a-timestamp"), None);
+    }
+}
@@ -1,19 +1,34 @@
 #[macro_use]
 extern crate lazy_static;
 
-use regex::Regex;
+mod copy;
+mod filter;
+mod progress;
+mod retry;
+mod s3path;
+
+use copy::cp_action;
+use filter::{Filter, FilterArgs};
+use progress::{print_summary, Progress, Stats};
+use retry::Semaphore;
 use rusoto_core::{HttpClient, Region};
-use rusoto_credential::EnvironmentProvider;
-use rusoto_s3::{
-    GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3,
+use rusoto_credential::{
+    AwsCredentials, CredentialsError, EnvironmentProvider,
+    InstanceMetadataProvider, ProfileProvider, ProvideAwsCredentials,
 };
+use rusoto_s3::{Delete, DeleteObjectsRequest, ObjectIdentifier, S3Client, S3};
+use rusoto_sts::WebIdentityProvider;
+use s3path::S3Path;
+use std::process::Command;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use structopt::StructOpt;
 use tokio::prelude::{future, Future};
 use tokio::runtime;
 
-type Error = Box<dyn std::error::Error>;
+pub(crate) type Error = Box<dyn std::error::Error>;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -21,43 +36,125 @@ type Error = Box<dyn std::error::Error>;
     about = "Rust-based S3 CLI catered for object transfers"
 )]
 struct Args {
+    /// AWS region (or S3-compatible equivalent) for the source client
+    #[structopt(long, default_value = "ap-southeast-1")]
+    region: String,
+
+    /// AWS region (or S3-compatible equivalent) for the destination
+    /// client; defaults to the same value as `--region`
+    #[structopt(long = "dst-region")]
+    dst_region: Option<String>,
+
+    /// Custom endpoint (e.g. a MinIO or Garage instance) for the source
+    /// client, used together with `--region` to build a `Region::Custom`
+    #[structopt(long)]
+    endpoint: Option<String>,
+
+    /// Custom endpoint for the destination client
+    #[structopt(long = "dst-endpoint")]
+    dst_endpoint: Option<String>,
+
+    /// Credential source for the source client: env, profile,
+    /// instance-metadata or web-identity
+    #[structopt(long, default_value = "env")]
+    credentials: CredentialsKind,
+
+    /// Credential source for the destination client; defaults to the
+    /// `DST_AWS`-prefixed environment provider to preserve prior behavior
+    #[structopt(long = "dst-credentials", default_value = "env")]
+    dst_credentials: CredentialsKind,
+
     /// Subcommand option
     #[structopt(subcommand)]
     subcommand: Subcommand,
 }
 
-struct S3Path {
-    pub bucket: String,
-    pub key: String,
+/// Selects which `rusoto_credential` provider backs a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CredentialsKind {
+    Env,
+    Profile,
+    InstanceMetadata,
+    WebIdentity,
 }
 
-// impl S3Path {
-//     pub fn is_dir(&self) -> bool {
-//         self.key.ends_with("/")
-//     }
-// }
-
-impl FromStr for S3Path {
+impl FromStr for CredentialsKind {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        lazy_static! {
-            static ref RE: Regex =
-                Regex::new(r"^s3://(.+?)(?:/(.*))?$").unwrap();
+        match s {
+            "env" => Ok(CredentialsKind::Env),
+            "profile" => Ok(CredentialsKind::Profile),
+            "instance-metadata" => Ok(CredentialsKind::InstanceMetadata),
+            "web-identity" => Ok(CredentialsKind::WebIdentity),
+            other => Err(format!("unknown credentials source: {}", other).into()),
         }
+    }
+}
 
-        let caps = RE.captures(s).unwrap();
-        let bucket = caps.get(1).unwrap().as_str().to_owned();
+/// Wraps whichever concrete `ProvideAwsCredentials` implementation was
+/// selected via `--credentials`/`--dst-credentials` behind a single type
+/// so both clients can be built the same way regardless of the choice.
+enum Credentials {
+    Env(EnvironmentProvider),
+    Profile(ProfileProvider),
+    InstanceMetadata(InstanceMetadataProvider),
+    WebIdentity(WebIdentityProvider),
+}
 
-        let key = match caps.get(2) {
-            Some(key) => key.as_str().to_owned(),
-            None => "".to_owned(),
-        };
+impl ProvideAwsCredentials for Credentials {
+    type Future =
+        Box<dyn Future<Item = AwsCredentials, Error = CredentialsError> + Send>;
 
-        Ok(S3Path { bucket, key })
+    fn credentials(&self) -> Self::Future {
+        match self {
+            Credentials::Env(provider) => Box::new(provider.credentials()),
+            Credentials::Profile(provider) => Box::new(provider.credentials()),
+            Credentials::InstanceMetadata(provider) => {
+                Box::new(provider.credentials())
+            }
+            Credentials::WebIdentity(provider) => {
+                Box::new(provider.credentials())
+            }
+        }
     }
 }
 
+/// Builds the credential provider for `kind`, using `env_prefix` (if any)
+/// for the `env` variant so the destination client can keep reading
+/// `DST_AWS`-prefixed environment variables.
+fn build_credentials(
+    kind: CredentialsKind,
+    env_prefix: Option<&str>,
+) -> Result<Credentials, Error> {
+    Ok(match kind {
+        CredentialsKind::Env => Credentials::Env(match env_prefix {
+            Some(prefix) => EnvironmentProvider::with_prefix(prefix),
+            None => EnvironmentProvider::default(),
+        }),
+        CredentialsKind::Profile => Credentials::Profile(ProfileProvider::new()?),
+        CredentialsKind::InstanceMetadata => {
+            Credentials::InstanceMetadata(InstanceMetadataProvider::new())
+        }
+        CredentialsKind::WebIdentity => {
+            Credentials::WebIdentity(WebIdentityProvider::from_k8s_env())
+        }
+    })
+}
+
+/// Builds a `Region`, routing through `Region::Custom` when `endpoint` is
+/// set so S3-compatible backends like MinIO or Garage can be targeted.
+fn build_region(name: &str, endpoint: Option<String>) -> Result<Region, Error> {
+    Ok(match endpoint {
+        Some(endpoint) => Region::Custom {
+            name: name.to_owned(),
+            endpoint,
+        },
+        None => Region::from_str(name)
+            .map_err(|_| format!("invalid region: {}", name))?,
+    })
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "Rustree subcommand", about = "Rustree subcommand options")]
 enum Subcommand {
@@ -73,120 +170,146 @@ enum Subcommand {
         /// Source object path to copy from
         #[structopt()]
         dst: String,
+
+        /// Always stream each object through GET+PUT instead of issuing a
+        /// server-side CopyObject/multipart copy request
+        #[structopt(long = "force-download")]
+        force_download: bool,
+
+        /// Part size in bytes used when streaming a GET+PUT upload through
+        /// the multipart upload path
+        #[structopt(long = "part-size", default_value = "8388608")]
+        part_size: u64,
+
+        /// Maximum number of objects copied concurrently
+        #[structopt(long = "max-concurrency", default_value = "16")]
+        max_concurrency: usize,
+
+        /// Suppress the progress bars, printing only the final summary
+        #[structopt(long)]
+        quiet: bool,
+
+        /// Storage class to apply to every copied object, overriding the
+        /// source object's own storage class (e.g. `STANDARD_IA`)
+        #[structopt(long = "storage-class")]
+        storage_class: Option<String>,
+
+        /// Don't carry over the source object's tags
+        #[structopt(long = "no-tags")]
+        no_tags: bool,
+
+        #[structopt(flatten)]
+        filter: FilterArgs,
     },
-}
 
-fn cp_action(
-    s3: &Arc<Mutex<S3Client>>,
-    dst_s3: &Arc<Mutex<S3Client>>,
-    src_path: &Arc<Mutex<S3Path>>,
-    dst_path: &Arc<Mutex<S3Path>>,
-    matching_obj: &rusoto_s3::Object,
-) -> Result<(), Error> {
-    let (src_bucket, src_key) = {
-        let src_path = src_path.lock().unwrap();
-        (src_path.bucket.clone(), src_path.key.clone())
-    };
-
-    let get_obj_req = GetObjectRequest {
-        bucket: src_bucket,
-        key: matching_obj.key.clone().unwrap(),
-        ..Default::default()
-    };
-
-    let rel_key = get_obj_req
-        .key
-        .trim_start_matches(&src_key)
-        .trim_start_matches('/')
-        .to_owned();
-
-    let get_obj_output = s3.lock().unwrap().get_object(get_obj_req).sync()?;
-
-    let (dst_bucket, dst_key) = {
-        let dst_path = dst_path.lock().unwrap();
-        (dst_path.bucket.clone(), dst_path.key.clone())
-    };
-
-    let dst_path_key = dst_key.trim_end_matches('/');
-    let dst_key = format!("{}/{}", dst_path_key, rel_key,);
-
-    println!(
-        "{} -> {}, content-length: {}",
-        rel_key,
-        dst_key,
-        get_obj_output.content_length.unwrap()
-    );
-
-    // dst
-    let put_obj_req = PutObjectRequest {
-        bucket: dst_bucket,
-        key: dst_key,
-        body: get_obj_output.body,
-        content_disposition: get_obj_output.content_disposition,
-        content_language: get_obj_output.content_language,
-        content_length: get_obj_output.content_length,
-        content_type: get_obj_output.content_type,
-        metadata: get_obj_output.metadata,
-        ..Default::default()
-    };
-
-    dst_s3.lock().unwrap().put_object(put_obj_req).sync()?;
+    #[structopt(name = "ls", about = "List objects matching a prefix")]
+    Ls {
+        /// Source object path to list
+        #[structopt()]
+        src: String,
+
+        #[structopt(flatten)]
+        filter: FilterArgs,
+    },
 
-    Ok(())
+    #[structopt(name = "rm", about = "Remove objects matching a prefix")]
+    Rm {
+        /// Source object path to remove from
+        #[structopt()]
+        src: String,
+
+        #[structopt(flatten)]
+        filter: FilterArgs,
+    },
+
+    #[structopt(
+        name = "exec",
+        about = "Run a command for each object matching a prefix"
+    )]
+    Exec {
+        /// Source object path to walk
+        #[structopt()]
+        src: String,
+
+        /// Command template run per matching key; `{}` is replaced with
+        /// the object key
+        #[structopt(long = "cmd")]
+        cmd: String,
+
+        #[structopt(flatten)]
+        filter: FilterArgs,
+    },
 }
 
 fn main() -> Result<(), Error> {
     let args = Args::from_args();
-    let provider = EnvironmentProvider::default();
-    let dst_provider = EnvironmentProvider::with_prefix("DST_AWS");
 
-    let s3 = Arc::new(Mutex::new(S3Client::new_with(
-        HttpClient::new()?,
-        provider,
-        Region::ApSoutheast1,
-    )));
+    let provider = build_credentials(args.credentials, None)?;
+    let dst_provider = build_credentials(args.dst_credentials, Some("DST_AWS"))?;
+
+    let region = build_region(&args.region, args.endpoint.clone())?;
+    let dst_region_name = args.dst_region.unwrap_or_else(|| args.region.clone());
+
+    // Only take the server-side copy fast path when source and destination
+    // share a region, endpoint and credentials source; otherwise
+    // `CopyObject` is guaranteed to fail with a cross-account/cross-region
+    // error on every object before the GET+PUT fallback kicks in.
+    let same_destination = args.region == dst_region_name
+        && args.endpoint == args.dst_endpoint
+        && args.credentials == args.dst_credentials;
+
+    let dst_region = build_region(&dst_region_name, args.dst_endpoint)?;
 
-    let dst_s3 = Arc::new(Mutex::new(S3Client::new_with(
-        HttpClient::new()?,
-        dst_provider,
-        Region::ApSoutheast1,
-    )));
+    // `S3Client` is `Clone + Send + Sync` (a thin handle around an `Arc`'d
+    // inner client), so it's shared by cloning rather than behind a
+    // `Mutex` — a mutex here would serialize every request onto a single
+    // in-flight call per client, defeating `--max-concurrency`.
+    let s3 = S3Client::new_with(HttpClient::new()?, provider, region);
+    let dst_s3 = S3Client::new_with(HttpClient::new()?, dst_provider, dst_region);
 
     match args.subcommand {
-        Subcommand::Cp { src, dst } => {
+        Subcommand::Cp {
+            src,
+            dst,
+            force_download,
+            part_size,
+            max_concurrency,
+            quiet,
+            storage_class,
+            no_tags,
+            filter,
+        } => {
             let src_path = Arc::new(Mutex::new(S3Path::from_str(&src)?));
             let dst_path = Arc::new(Mutex::new(S3Path::from_str(&dst)?));
+            let filter = Filter::new(&filter)?;
 
             let mut rt = runtime::Builder::new().blocking_threads(4).build()?;
+            let semaphore = Arc::new(Semaphore::new(max_concurrency));
+            let failures = Arc::new(AtomicUsize::new(0));
+            let stats = Arc::new(Mutex::new(Stats::default()));
+            let progress = Progress::new(quiet);
+            let render_handle = progress.run_render_thread();
+            let started_at = Instant::now();
+            let tags = !no_tags;
+
+            filter::for_each_matching_object(
+                &s3,
+                &src_path,
+                &filter,
+                |matching_obj| {
+                    progress.object_discovered();
+                    semaphore.acquire();
 
-            // Get initial matching prefixes objects
-            let mut is_truncated = true;
-            let mut next_continuation_token = None;
-
-            while is_truncated {
-                let list_objs_req = {
-                    let src_path = src_path.lock().unwrap();
-
-                    ListObjectsV2Request {
-                        bucket: src_path.bucket.clone(),
-                        prefix: Some(src_path.key.clone()),
-                        continuation_token: next_continuation_token,
-                        ..Default::default()
-                    }
-                };
-
-                let list_obj_output =
-                    s3.lock().unwrap().list_objects_v2(list_objs_req).sync()?;
-
-                let matching_objs =
-                    list_obj_output.contents.unwrap().into_iter();
-
-                // Perform the actual looping src to dst copy
-                for matching_obj in matching_objs {
                     let s3 = s3.clone();
                     let dst_s3 = dst_s3.clone();
                     let src_path = src_path.clone();
                     let dst_path = dst_path.clone();
+                    let semaphore = semaphore.clone();
+                    let failures = failures.clone();
+                    let stats = stats.clone();
+                    let progress = progress.clone();
+                    let storage_class = storage_class.clone();
+                    let same_destination = same_destination;
 
                     rt.spawn(
                         future::lazy(move || {
@@ -198,11 +321,23 @@ fn main() -> Result<(), Error> {
                                         &src_path,
                                         &dst_path,
                                         &matching_obj,
+                                        force_download,
+                                        same_destination,
+                                        part_size,
+                                        storage_class.as_deref(),
+                                        tags,
+                                        &stats,
+                                        &progress,
                                     );
 
                                     if let Err(err) = res {
                                         eprintln!("Copy action error: {}", err);
+                                        failures.fetch_add(1, Ordering::SeqCst);
+                                        stats.lock().unwrap().record_failure();
                                     }
+
+                                    progress.object_completed();
+                                    semaphore.release();
                                 })
                             })
                         })
@@ -210,16 +345,136 @@ fn main() -> Result<(), Error> {
                             eprintln!("Future lazy error: {}", err);
                         }),
                     );
-                }
 
-                is_truncated = list_obj_output.is_truncated.unwrap_or(false);
-                next_continuation_token =
-                    list_obj_output.next_continuation_token.clone();
-            }
+                    Ok(())
+                },
+            )?;
 
             rt.shutdown_on_idle().wait().unwrap();
+
+            progress.finish();
+            drop(progress);
+            render_handle.join().unwrap();
+
+            print_summary(&stats.lock().unwrap(), started_at.elapsed());
+
+            let failed = failures.load(Ordering::SeqCst);
+
+            if failed > 0 {
+                return Err(format!("{} object(s) failed to copy", failed).into());
+            }
+        }
+
+        Subcommand::Ls { src, filter } => {
+            let src_path = Arc::new(Mutex::new(S3Path::from_str(&src)?));
+            let filter = Filter::new(&filter)?;
+
+            filter::for_each_matching_object(&s3, &src_path, &filter, |obj| {
+                println!(
+                    "{}\t{}\t{}",
+                    obj.key.unwrap_or_default(),
+                    obj.size.unwrap_or(0),
+                    obj.last_modified.unwrap_or_default()
+                );
+
+                Ok(())
+            })?;
+        }
+
+        Subcommand::Rm { src, filter } => {
+            let src_path = Arc::new(Mutex::new(S3Path::from_str(&src)?));
+            let filter = Filter::new(&filter)?;
+            let bucket = src_path.lock().unwrap().bucket.clone();
+
+            let flush = |batch: &mut Vec<ObjectIdentifier>| -> Result<(), Error> {
+                if batch.is_empty() {
+                    return Ok(());
+                }
+
+                let delete_req = DeleteObjectsRequest {
+                    bucket: bucket.clone(),
+                    delete: Delete {
+                        objects: batch.drain(..).collect(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+
+                s3.delete_objects(delete_req).sync()?;
+
+                Ok(())
+            };
+
+            let mut batch = Vec::new();
+
+            filter::for_each_matching_object(&s3, &src_path, &filter, |obj| {
+                batch.push(ObjectIdentifier {
+                    key: obj.key.unwrap(),
+                    ..Default::default()
+                });
+
+                if batch.len() == 1000 {
+                    flush(&mut batch)?;
+                }
+
+                Ok(())
+            })?;
+
+            flush(&mut batch)?;
+        }
+
+        Subcommand::Exec { src, cmd, filter } => {
+            let src_path = Arc::new(Mutex::new(S3Path::from_str(&src)?));
+            let filter = Filter::new(&filter)?;
+
+            filter::for_each_matching_object(&s3, &src_path, &filter, |obj| {
+                let key = obj.key.unwrap_or_default();
+                let rendered = cmd.replace("{}", &key);
+
+                let status = Command::new("sh").arg("-c").arg(&rendered).status()?;
+
+                if !status.success() {
+                    eprintln!(
+                        "Command for {} exited with {}",
+                        key, status
+                    );
+                }
+
+                Ok(())
+            })?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_region_uses_custom_when_endpoint_is_set() {
+        let region =
+            build_region("garage", Some("http://localhost:3900".to_owned())).unwrap();
+
+        assert_eq!(
+            region,
+            Region::Custom {
+                name: "garage".to_owned(),
+                endpoint: "http://localhost:3900".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn build_region_parses_a_known_region_name() {
+        let region = build_region("us-east-1", None).unwrap();
+
+        assert_eq!(region, Region::UsEast1);
+    }
+
+    #[test]
+    fn build_region_rejects_an_unparseable_region() {
+        assert!(build_region("not-a-real-region", None).is_err());
+    }
+}
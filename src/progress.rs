@@ -0,0 +1,122 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Drives the overall "objects discovered vs. completed" bar plus a
+/// per-object byte bar for multipart transfers, all rendered through a
+/// single `indicatif::MultiProgress`.
+#[derive(Clone)]
+pub(crate) struct Progress {
+    multi: Arc<MultiProgress>,
+    overall: ProgressBar,
+    quiet: bool,
+}
+
+impl Progress {
+    pub(crate) fn new(quiet: bool) -> Self {
+        let multi = Arc::new(MultiProgress::new());
+
+        let overall = multi.add(ProgressBar::new(0));
+        overall.set_style(
+            ProgressStyle::default_bar()
+                .template("objects [{bar:40}] {pos}/{len} ({elapsed})"),
+        );
+
+        if quiet {
+            overall.set_draw_target(ProgressDrawTarget::hidden());
+        }
+
+        Progress {
+            multi,
+            overall,
+            quiet,
+        }
+    }
+
+    /// Spawns the thread that actually renders the progress bars;
+    /// `indicatif` requires a dedicated thread pumping `MultiProgress`
+    /// while other threads update the bars it owns.
+    pub(crate) fn run_render_thread(&self) -> thread::JoinHandle<()> {
+        let multi = self.multi.clone();
+
+        thread::spawn(move || {
+            let _ = multi.join();
+        })
+    }
+
+    /// Called once per object as soon as it's discovered by the listing
+    /// walk, before any copy work starts on it.
+    pub(crate) fn object_discovered(&self) {
+        self.overall.inc_length(1);
+    }
+
+    /// Called once an object has finished copying, whether it succeeded
+    /// or failed.
+    pub(crate) fn object_completed(&self) {
+        self.overall.inc(1);
+    }
+
+    /// Creates a byte-level bar for a single multipart transfer, advanced
+    /// as each part completes.
+    pub(crate) fn part_bar(&self, key: &str, total_bytes: u64) -> ProgressBar {
+        let bar = self.multi.add(ProgressBar::new(total_bytes));
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{msg} [{bar:30}] {bytes}/{total_bytes}"),
+        );
+        bar.set_message(key);
+
+        if self.quiet {
+            bar.set_draw_target(ProgressDrawTarget::hidden());
+        }
+
+        bar
+    }
+
+    /// Prints a status line above the bars instead of going straight to
+    /// stdout, which would otherwise race with the render thread and
+    /// garble the terminal whenever bars are visible. Prints nothing in
+    /// quiet mode.
+    pub(crate) fn println(&self, msg: impl AsRef<str>) {
+        if self.quiet {
+            return;
+        }
+
+        let _ = self.multi.println(msg);
+    }
+
+    pub(crate) fn finish(&self) {
+        self.overall.finish();
+    }
+}
+
+/// Running totals for the transfer summary printed at the end of a `cp`
+/// run.
+#[derive(Default)]
+pub(crate) struct Stats {
+    pub(crate) objects_copied: u64,
+    pub(crate) bytes_copied: u64,
+    pub(crate) failures: u64,
+}
+
+impl Stats {
+    pub(crate) fn record_success(&mut self, bytes: u64) {
+        self.objects_copied += 1;
+        self.bytes_copied += bytes;
+    }
+
+    pub(crate) fn record_failure(&mut self) {
+        self.failures += 1;
+    }
+}
+
+pub(crate) fn print_summary(stats: &Stats, elapsed: Duration) {
+    println!(
+        "Copied {} object(s), {} byte(s) in {:.2}s, {} failure(s)",
+        stats.objects_copied,
+        stats.bytes_copied,
+        elapsed.as_secs_f64(),
+        stats.failures,
+    );
+}
@@ -0,0 +1,84 @@
+use rand::Rng;
+use rusoto_core::RusotoError;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Base delay before the first retry.
+const BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// Upper bound on the backoff delay between retries.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Maximum number of attempts (the original call plus up to 4 retries).
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Runs `f`, retrying with exponential backoff and jitter on transient
+/// errors (dispatch failures, throttling, 5xx responses) up to
+/// [`MAX_ATTEMPTS`] times.
+pub(crate) fn with_retry<T, E, F>(mut f: F) -> Result<T, RusotoError<E>>
+where
+    F: FnMut() -> Result<T, RusotoError<E>>,
+{
+    let mut attempt = 1;
+    let mut delay = BASE_DELAY;
+
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < MAX_ATTEMPTS && is_retryable(&err) => {
+                let jitter = rand::thread_rng().gen_range(0, delay.as_millis() as u64 + 1);
+                thread::sleep(Duration::from_millis(jitter));
+
+                attempt += 1;
+                delay = std::cmp::min(delay * 2, MAX_DELAY);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Whether `err` represents a transient condition worth retrying: a
+/// dispatch-level failure, or a throttling/5xx response from the service.
+fn is_retryable<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(response) => {
+            let status = response.status.as_u16();
+            status == 429 || (500..600).contains(&status)
+        }
+        _ => false,
+    }
+}
+
+/// Minimal counting semaphore used to bound the number of concurrently
+/// in-flight requests (multipart upload parts, spawned copy tasks, ...).
+pub(crate) struct Semaphore {
+    state: Mutex<usize>,
+    cond: Condvar,
+}
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(permits),
+            cond: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn acquire(&self) {
+        let mut permits = self.state.lock().unwrap();
+
+        while *permits == 0 {
+            permits = self.cond.wait(permits).unwrap();
+        }
+
+        *permits -= 1;
+    }
+
+    pub(crate) fn release(&self) {
+        let mut permits = self.state.lock().unwrap();
+        *permits += 1;
+        self.cond.notify_one();
+    }
+}
@@ -0,0 +1,35 @@
+use crate::Error;
+use regex::Regex;
+use std::str::FromStr;
+
+pub(crate) struct S3Path {
+    pub bucket: String,
+    pub key: String,
+}
+
+// impl S3Path {
+//     pub fn is_dir(&self) -> bool {
+//         self.key.ends_with("/")
+//     }
+// }
+
+impl FromStr for S3Path {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        lazy_static! {
+            static ref RE: Regex =
+                Regex::new(r"^s3://(.+?)(?:/(.*))?$").unwrap();
+        }
+
+        let caps = RE.captures(s).unwrap();
+        let bucket = caps.get(1).unwrap().as_str().to_owned();
+
+        let key = match caps.get(2) {
+            Some(key) => key.as_str().to_owned(),
+            None => "".to_owned(),
+        };
+
+        Ok(S3Path { bucket, key })
+    }
+}